@@ -0,0 +1,388 @@
+// Cluster federation: lets more than one Brongnal server node share a
+// single deployment. Each identity is sharded to a "home" node by
+// `ClusterMetadata`; a node that isn't an identity's home forwards
+// `send_message`/`request_pre_keys` to the node that is, over an
+// inter-node connection authenticated with node key material rather than
+// any user's keys.
+use anyhow::{ensure, Context, Result};
+use blake2::{Blake2b512, Digest};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use proto::service::brongnal_client::BrongnalClient;
+use server::proto;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::metadata::MetadataMap;
+use tonic::transport::Channel;
+use tonic::Request;
+
+pub type NodeId = String;
+
+/// One node in the cluster: its stable ID (used for sharding and for
+/// authenticating node-to-node connections) and the endpoint other nodes
+/// dial to reach it.
+#[derive(Clone, Debug)]
+pub struct NodeAddr {
+    pub id: NodeId,
+    pub endpoint: String,
+}
+
+/// Maps identities to the node that owns their live state (the
+/// `receivers` map and message queue). Read-only and swappable so a
+/// deployment can reshard without touching the routing logic in
+/// `SqliteBrongnal`.
+pub trait ClusterMetadata: Send + Sync {
+    fn local_node(&self) -> &NodeId;
+    fn home_node(&self, identity: &str) -> &NodeAddr;
+
+    fn is_local(&self, identity: &str) -> bool {
+        &self.home_node(identity).id == self.local_node()
+    }
+}
+
+/// Shards identities across a fixed node list by a stable hash of the
+/// identity string, mod the node count. Simple, and consistent enough for
+/// a small cluster; a deployment that outgrows it can swap in a
+/// consistent-hash-ring implementation of `ClusterMetadata` without
+/// changing any routing call sites.
+pub struct StaticClusterMetadata {
+    local_node: NodeId,
+    nodes: Vec<NodeAddr>,
+}
+
+impl StaticClusterMetadata {
+    pub fn new(local_node: NodeId, nodes: Vec<NodeAddr>) -> Result<Self> {
+        ensure!(!nodes.is_empty(), "Cluster metadata needs at least one node.");
+        Ok(StaticClusterMetadata { local_node, nodes })
+    }
+
+    fn shard_hash(identity: &str) -> u64 {
+        let mut hasher = Blake2b512::new();
+        hasher.update(b"brongnal-cluster-shard");
+        hasher.update(identity.as_bytes());
+        let digest = hasher.finalize();
+        u64::from_be_bytes(digest[0..8].try_into().unwrap())
+    }
+}
+
+impl ClusterMetadata for StaticClusterMetadata {
+    fn local_node(&self) -> &NodeId {
+        &self.local_node
+    }
+
+    fn home_node(&self, identity: &str) -> &NodeAddr {
+        let index = (Self::shard_hash(identity) as usize) % self.nodes.len();
+        &self.nodes[index]
+    }
+}
+
+/// A node's own identity, distinct from any user's identity key, used to
+/// authenticate node-to-node RPCs.
+pub struct NodeIdentity {
+    pub id: NodeId,
+    signing_key: SigningKey,
+}
+
+impl NodeIdentity {
+    pub fn new(id: NodeId, signing_key: SigningKey) -> Self {
+        NodeIdentity { id, signing_key }
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    /// Signs a short-lived token proving this node's identity to the peer
+    /// it's connecting to, bound to its issue time (in seconds) so a
+    /// captured token is only accepted for `NODE_TOKEN_LIFETIME_SECS`.
+    fn issue_token(&self) -> Result<NodeAuthToken> {
+        let issued_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let signature = self
+            .signing_key
+            .sign(&Self::signed_bytes(&self.id, issued_at));
+        Ok(NodeAuthToken {
+            node_id: self.id.clone(),
+            issued_at,
+            signature,
+        })
+    }
+
+    fn signed_bytes(node_id: &str, issued_at: u64) -> Vec<u8> {
+        let mut bytes = node_id.as_bytes().to_vec();
+        bytes.extend_from_slice(&issued_at.to_be_bytes());
+        bytes
+    }
+}
+
+impl NodeAuthToken {
+    /// Parses the `x-brongnal-node-*` headers `authenticate` attaches to a
+    /// forwarded RPC, if present. `Ok(None)` means this call wasn't
+    /// forwarded from another node — an ordinary client call, which should
+    /// be let through rather than rejected.
+    pub fn from_metadata(metadata: &MetadataMap) -> Result<Option<Self>> {
+        let Some(node_id) = metadata.get("x-brongnal-node-id") else {
+            return Ok(None);
+        };
+        let node_id = node_id
+            .to_str()
+            .context("Node ID is not valid metadata.")?
+            .to_owned();
+        let issued_at: u64 = metadata
+            .get("x-brongnal-node-issued-at")
+            .context("Forwarded request missing issued-at.")?
+            .to_str()
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .context("Issued-at is not a valid integer.")?;
+        let signature_bytes = hex::decode(
+            metadata
+                .get("x-brongnal-node-signature")
+                .context("Forwarded request missing signature.")?
+                .to_str()
+                .context("Signature is not valid metadata.")?,
+        )
+        .context("Signature is not valid hex.")?;
+        let signature = Signature::from_bytes(
+            signature_bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Signature must be 64 bytes."))?,
+        );
+        Ok(Some(NodeAuthToken {
+            node_id,
+            issued_at,
+            signature,
+        }))
+    }
+}
+
+/// A node-to-node auth token, carried as RPC metadata on federated calls.
+/// The receiving node verifies it against the sending node's known
+/// `VerifyingKey` (distributed out of band, like the obfuscated transport's
+/// `NodeIdentity`).
+pub struct NodeAuthToken {
+    pub node_id: NodeId,
+    pub issued_at: u64,
+    pub signature: Signature,
+}
+
+const NODE_TOKEN_LIFETIME_SECS: u64 = 60;
+
+impl NodeAuthToken {
+    pub fn verify(&self, verifying_key: &VerifyingKey) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        ensure!(
+            now.saturating_sub(self.issued_at) < NODE_TOKEN_LIFETIME_SECS,
+            "Node auth token has expired."
+        );
+        verifying_key
+            .verify(
+                &NodeIdentity::signed_bytes(&self.node_id, self.issued_at),
+                &self.signature,
+            )
+            .context("Node auth token signature is invalid.")
+    }
+}
+
+/// Caches authenticated connections to other nodes in the cluster and
+/// forwards RPCs that belong to identities homed elsewhere.
+pub struct FederationClient {
+    identity: NodeIdentity,
+    peer_keys: HashMap<NodeId, VerifyingKey>,
+    connections: Mutex<HashMap<NodeId, BrongnalClient<Channel>>>,
+}
+
+impl FederationClient {
+    pub fn new(identity: NodeIdentity, peer_keys: HashMap<NodeId, VerifyingKey>) -> Self {
+        FederationClient {
+            identity,
+            peer_keys,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Opens (or reuses) a cached connection to `node`. The connection itself
+    /// carries no node credentials; `authenticate` adds them per request.
+    async fn connect(&self, node: &NodeAddr) -> Result<BrongnalClient<Channel>> {
+        if let Some(client) = self.connections.lock().unwrap().get(&node.id) {
+            return Ok(client.clone());
+        }
+        let client = BrongnalClient::connect(node.endpoint.clone())
+            .await
+            .with_context(|| format!("Failed to connect to node {}.", node.id))?;
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(node.id.clone(), client.clone());
+        Ok(client)
+    }
+
+    /// Attaches a freshly signed node auth token to `request` so the home
+    /// node can authenticate the forward as coming from this node, not from
+    /// a user.
+    fn authenticate<T>(&self, mut request: Request<T>) -> Result<Request<T>> {
+        let token = self.identity.issue_token()?;
+        request.metadata_mut().insert(
+            "x-brongnal-node-id",
+            token.node_id.parse().context("Node ID is not valid metadata.")?,
+        );
+        request.metadata_mut().insert(
+            "x-brongnal-node-issued-at",
+            token
+                .issued_at
+                .to_string()
+                .parse()
+                .context("Issued-at is not valid metadata.")?,
+        );
+        request.metadata_mut().insert(
+            "x-brongnal-node-signature",
+            hex::encode(token.signature.to_bytes())
+                .parse()
+                .context("Signature is not valid metadata.")?,
+        );
+        Ok(request)
+    }
+
+    /// Forwards a `send_message` that belongs to an identity homed on
+    /// `node` instead of handling it locally.
+    pub async fn forward_send_message(
+        &self,
+        node: &NodeAddr,
+        request: proto::service::SendMessageRequest,
+    ) -> Result<()> {
+        let mut client = self.connect(node).await?;
+        let request = self.authenticate(Request::new(request))?;
+        client
+            .send_message(request)
+            .await
+            .with_context(|| format!("Forwarding send_message to node {} failed.", node.id))?;
+        Ok(())
+    }
+
+    /// Forwards a `request_pre_keys` that belongs to an identity homed on
+    /// `node` instead of handling it locally.
+    pub async fn forward_request_pre_keys(
+        &self,
+        node: &NodeAddr,
+        request: proto::service::RequestPreKeysRequest,
+    ) -> Result<proto::service::PreKeyBundle> {
+        let mut client = self.connect(node).await?;
+        let request = self.authenticate(Request::new(request))?;
+        let response = client
+            .request_pre_keys(request)
+            .await
+            .with_context(|| format!("Forwarding request_pre_keys to node {} failed.", node.id))?;
+        Ok(response.into_inner())
+    }
+
+    /// Forwards a `retrieve_messages` stream for an identity homed on
+    /// `node` instead of serving it from this node's own SQLite queue. The
+    /// incoming request's metadata (including the `x-brongnal-cursor`
+    /// resumption header) is carried along unchanged so the home node sees
+    /// the same resumption state a direct connection would have given it.
+    pub async fn forward_retrieve_messages(
+        &self,
+        node: &NodeAddr,
+        request: Request<proto::service::RetrieveMessagesRequest>,
+    ) -> Result<tonic::Streaming<proto::service::Message>> {
+        let mut client = self.connect(node).await?;
+        let request = self.authenticate(request)?;
+        let response = client
+            .retrieve_messages(request)
+            .await
+            .with_context(|| format!("Forwarding retrieve_messages to node {} failed.", node.id))?;
+        Ok(response.into_inner())
+    }
+
+    /// Verifies an inbound node auth token against the claimed node's known
+    /// public key, for use on the receiving side of a forwarded RPC.
+    pub fn verify_incoming(&self, token: &NodeAuthToken) -> Result<()> {
+        let verifying_key = self
+            .peer_keys
+            .get(&token.node_id)
+            .context("Unknown peer node id.")?;
+        token.verify(verifying_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(local_node: &str) -> StaticClusterMetadata {
+        StaticClusterMetadata::new(
+            local_node.to_owned(),
+            vec![
+                NodeAddr {
+                    id: "node-a".to_owned(),
+                    endpoint: "http://node-a".to_owned(),
+                },
+                NodeAddr {
+                    id: "node-b".to_owned(),
+                    endpoint: "http://node-b".to_owned(),
+                },
+                NodeAddr {
+                    id: "node-c".to_owned(),
+                    endpoint: "http://node-c".to_owned(),
+                },
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn home_node_is_deterministic_for_the_same_identity() {
+        let cluster = cluster("node-a");
+        let first = cluster.home_node("alice").id.clone();
+        let second = cluster.home_node("alice").id.clone();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn home_node_agrees_across_differently_sharded_views_of_the_cluster() {
+        // Every node in the cluster should compute the same home node for a
+        // given identity, regardless of which node is "local" — sharding
+        // must not depend on `local_node`.
+        let a = cluster("node-a").home_node("alice").id.clone();
+        let b = cluster("node-b").home_node("alice").id.clone();
+        let c = cluster("node-c").home_node("alice").id.clone();
+        assert_eq!(a, b);
+        assert_eq!(b, c);
+    }
+
+    #[test]
+    fn is_local_agrees_with_home_node() {
+        let cluster = cluster("node-a");
+        let home = cluster.home_node("alice").id.clone();
+        assert_eq!(cluster.is_local("alice"), home == "node-a");
+    }
+
+    #[test]
+    fn node_auth_token_round_trips_through_metadata() {
+        let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let identity = NodeIdentity::new("node-a".to_owned(), signing_key);
+        let token = identity.issue_token().unwrap();
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert("x-brongnal-node-id", token.node_id.parse().unwrap());
+        metadata.insert(
+            "x-brongnal-node-issued-at",
+            token.issued_at.to_string().parse().unwrap(),
+        );
+        metadata.insert(
+            "x-brongnal-node-signature",
+            hex::encode(token.signature.to_bytes()).parse().unwrap(),
+        );
+
+        let parsed = NodeAuthToken::from_metadata(&metadata).unwrap().unwrap();
+        assert_eq!(parsed.node_id, token.node_id);
+        assert_eq!(parsed.issued_at, token.issued_at);
+        parsed.verify(&identity.verifying_key()).unwrap();
+    }
+
+    #[test]
+    fn node_auth_token_absent_from_metadata_is_not_an_error() {
+        let metadata = MetadataMap::new();
+        assert!(NodeAuthToken::from_metadata(&metadata).unwrap().is_none());
+    }
+}
@@ -0,0 +1,740 @@
+// CONIKS-style verifiable key directory: an append-only Merkle tree over
+// served identity keys, committed each epoch into a signed tree head (STH)
+// so a client can detect a server that substitutes a fake key for a
+// registered identity.
+//
+// Proofs are always recomputed from the immutable leaf hashes in
+// `kt_leaves` rather than from a mutable per-level node cache: the
+// RFC6962 tree shape is *not* stable under growth (a given `(level,
+// node_index)` covers a different leaf range at different tree sizes), so
+// caching nodes keyed only by position would silently return stale/wrong
+// hashes for any tree size other than the current one — exactly the bug
+// that would make `consistency_proof` for an older `old_size` produce
+// garbage. Recomputing from leaves is more work per call, but this
+// directory is sized for "one leaf per registration", not a web-scale log.
+use anyhow::{ensure, Context, Result};
+use blake2::{Blake2b512, Digest};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rusqlite::Connection;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `H(identity || identity_key || epoch)`: the leaf hash committed to the
+/// tree for one registration event. Re-registering the same identity (e.g.
+/// on SPK rotation) appends a new leaf rather than mutating an old one, so
+/// the whole history stays auditable.
+pub fn leaf_hash(identity: &str, identity_key: &VerifyingKey, epoch: u64) -> [u8; 32] {
+    let mut hasher = Blake2b512::new();
+    hasher.update(b"brongnal-kt-leaf");
+    hasher.update(identity.as_bytes());
+    hasher.update(identity_key.as_bytes());
+    hasher.update(epoch.to_be_bytes());
+    let digest = hasher.finalize();
+    let mut out = [0u8; 32];
+    out.clone_from_slice(&digest[0..32]);
+    out
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Blake2b512::new();
+    hasher.update(b"brongnal-kt-node");
+    hasher.update(left);
+    hasher.update(right);
+    let digest = hasher.finalize();
+    let mut out = [0u8; 32];
+    out.clone_from_slice(&digest[0..32]);
+    out
+}
+
+fn empty_hash() -> [u8; 32] {
+    let mut hasher = Blake2b512::new();
+    hasher.update(b"brongnal-kt-empty");
+    let digest = hasher.finalize();
+    let mut out = [0u8; 32];
+    out.clone_from_slice(&digest[0..32]);
+    out
+}
+
+/// The largest power of two strictly less than `n` (RFC6962's `k`), used to
+/// split a range of leaves into its canonical left/right subtrees.
+fn split_point(n: u64) -> u64 {
+    let mut k = 1;
+    while k * 2 < n {
+        k *= 2;
+    }
+    k
+}
+
+/// The Merkle Tree Hash of a leaf range, defined recursively exactly as in
+/// RFC6962: a single leaf hashes to itself, and a larger range splits at
+/// `split_point` and combines the two halves.
+fn mth(leaves: &[[u8; 32]]) -> [u8; 32] {
+    match leaves {
+        [] => empty_hash(),
+        [leaf] => *leaf,
+        _ => {
+            let k = split_point(leaves.len() as u64) as usize;
+            let (left, right) = leaves.split_at(k);
+            node_hash(&mth(left), &mth(right))
+        }
+    }
+}
+
+/// A signed tree head: the server's commitment to the directory's state at
+/// a point in time. Clients persist the last one they've seen and refuse
+/// any bundle whose inclusion proof doesn't chain forward from it.
+#[derive(Clone, Debug)]
+pub struct SignedTreeHead {
+    pub root: [u8; 32],
+    pub tree_size: u64,
+    pub epoch: u64,
+    pub signature: Signature,
+}
+
+impl SignedTreeHead {
+    fn signed_bytes(root: &[u8; 32], tree_size: u64, epoch: u64) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(48);
+        bytes.extend_from_slice(root);
+        bytes.extend_from_slice(&tree_size.to_be_bytes());
+        bytes.extend_from_slice(&epoch.to_be_bytes());
+        bytes
+    }
+
+    fn sign(signing_key: &SigningKey, root: [u8; 32], tree_size: u64, epoch: u64) -> Self {
+        let signature = signing_key.sign(&Self::signed_bytes(&root, tree_size, epoch));
+        SignedTreeHead {
+            root,
+            tree_size,
+            epoch,
+            signature,
+        }
+    }
+
+    pub fn verify(&self, verifying_key: &VerifyingKey) -> Result<()> {
+        verifying_key
+            .verify(
+                &Self::signed_bytes(&self.root, self.tree_size, self.epoch),
+                &self.signature,
+            )
+            .context("Signed tree head signature is invalid.")
+    }
+
+    /// Fixed-width wire encoding (`root || tree_size || epoch || signature`)
+    /// for carrying an STH to a client over gRPC response metadata, since
+    /// this snapshot has no `.proto` field for it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + 8 + 8 + 64);
+        out.extend_from_slice(&self.root);
+        out.extend_from_slice(&self.tree_size.to_be_bytes());
+        out.extend_from_slice(&self.epoch.to_be_bytes());
+        out.extend_from_slice(&self.signature.to_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() == 32 + 8 + 8 + 64, "Signed tree head has the wrong length.");
+        let root: [u8; 32] = bytes[0..32].try_into().unwrap();
+        let tree_size = u64::from_be_bytes(bytes[32..40].try_into().unwrap());
+        let epoch = u64::from_be_bytes(bytes[40..48].try_into().unwrap());
+        let signature = Signature::from_bytes(bytes[48..112].try_into().unwrap());
+        Ok(SignedTreeHead {
+            root,
+            tree_size,
+            epoch,
+            signature,
+        })
+    }
+}
+
+/// One sibling on the path from a leaf (or a consistency-proof boundary) up
+/// to the root. `is_left` says which side of the combination `hash`
+/// occupies, so the verifier doesn't need to re-derive tree shape from
+/// position arithmetic alone.
+#[derive(Clone, Copy, Debug)]
+struct PathStep {
+    hash: [u8; 32],
+    is_left: bool,
+}
+
+fn combine(current: [u8; 32], step: &PathStep) -> [u8; 32] {
+    if step.is_left {
+        node_hash(&step.hash, &current)
+    } else {
+        node_hash(&current, &step.hash)
+    }
+}
+
+/// A Merkle inclusion proof: the sibling hashes needed to recompute the
+/// root from `leaf` at `leaf_index`, given a tree of `tree_size` leaves.
+#[derive(Clone, Debug)]
+pub struct InclusionProof {
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    path: Vec<PathStep>,
+}
+
+impl InclusionProof {
+    fn build(leaves: &[[u8; 32]], index: u64, path: &mut Vec<PathStep>) {
+        let n = leaves.len() as u64;
+        if n <= 1 {
+            return;
+        }
+        let k = split_point(n);
+        if index < k {
+            Self::build(&leaves[..k as usize], index, path);
+            path.push(PathStep {
+                hash: mth(&leaves[k as usize..]),
+                is_left: false,
+            });
+        } else {
+            Self::build(&leaves[k as usize..], index - k, path);
+            path.push(PathStep {
+                hash: mth(&leaves[..k as usize]),
+                is_left: true,
+            });
+        }
+    }
+
+    /// Recomputes the root from `leaf` and checks it matches `sth.root`.
+    pub fn verify(&self, leaf: [u8; 32], sth: &SignedTreeHead) -> Result<()> {
+        ensure!(
+            self.tree_size == sth.tree_size,
+            "Inclusion proof is for a different tree size than the STH."
+        );
+        let root = self.path.iter().fold(leaf, |current, step| combine(current, step));
+        ensure!(root == sth.root, "Inclusion proof does not chain to the STH root.");
+        Ok(())
+    }
+}
+
+/// A Merkle consistency proof between two tree sizes, letting a monitor
+/// verify the log was only ever appended to, never rewritten.
+///
+/// Every step covers a leaf range that's common to both the old and the
+/// new tree (by the append-only invariant, any range entirely inside
+/// `[0, old_size)` hashes identically in both trees); `shared_with_old`
+/// marks the subset of steps that are also needed to reconstruct the OLD
+/// root, so the verifier can fold the same step list into both the old and
+/// the new root and check both against the trusted STHs.
+#[derive(Clone, Debug)]
+pub struct ConsistencyProof {
+    pub old_size: u64,
+    pub new_size: u64,
+    steps: Vec<ConsistencyStep>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ConsistencyStep {
+    step: PathStep,
+    shared_with_old: bool,
+}
+
+impl ConsistencyProof {
+    /// Mirrors RFC6962's `SUBPROOF(m, D[0:n], b)`: walks down the new
+    /// tree's canonical recursive split, recording one step per level
+    /// until the remaining subtree's size equals `m` (the old boundary).
+    /// Returns the subtree's own root so the caller can combine it with
+    /// the sibling it's about to push.
+    fn subproof(leaves: &[[u8; 32]], m: u64, b: bool, steps: &mut Vec<ConsistencyStep>) -> [u8; 32] {
+        let n = leaves.len() as u64;
+        if m == n {
+            let root = mth(leaves);
+            if !b {
+                steps.push(ConsistencyStep {
+                    step: PathStep { hash: root, is_left: true },
+                    shared_with_old: true,
+                });
+            }
+            return root;
+        }
+        let k = split_point(n);
+        if m <= k {
+            let left = Self::subproof(&leaves[..k as usize], m, b, steps);
+            let right = mth(&leaves[k as usize..]);
+            steps.push(ConsistencyStep {
+                step: PathStep { hash: right, is_left: false },
+                shared_with_old: false,
+            });
+            node_hash(&left, &right)
+        } else {
+            let right = Self::subproof(&leaves[k as usize..], m - k, false, steps);
+            let left = mth(&leaves[..k as usize]);
+            steps.push(ConsistencyStep {
+                step: PathStep { hash: left, is_left: true },
+                shared_with_old: true,
+            });
+            node_hash(&left, &right)
+        }
+    }
+
+    fn build(leaves: &[[u8; 32]], old_size: u64, new_size: u64) -> Self {
+        let mut steps = Vec::new();
+        if old_size != 0 && old_size != new_size {
+            Self::subproof(leaves, old_size, true, &mut steps);
+        }
+        ConsistencyProof {
+            old_size,
+            new_size,
+            steps,
+        }
+    }
+
+    /// Folds the proof's steps into both the old and the new root and
+    /// checks them against the two trusted signed tree heads, confirming
+    /// `new` only ever appended to `old` and never rewrote it.
+    pub fn verify(&self, old: &SignedTreeHead, new: &SignedTreeHead) -> Result<()> {
+        ensure!(self.old_size == old.tree_size, "Proof old_size does not match STH.");
+        ensure!(self.new_size == new.tree_size, "Proof new_size does not match STH.");
+        ensure!(self.old_size <= self.new_size, "old_size must not exceed new_size.");
+
+        if self.old_size == 0 {
+            return Ok(());
+        }
+        if self.old_size == self.new_size {
+            ensure!(self.steps.is_empty(), "Unexpected proof steps for equal tree sizes.");
+            ensure!(old.root == new.root, "Tree heads of equal size disagree.");
+            return Ok(());
+        }
+
+        let mut steps = self.steps.iter();
+        let (mut old_root, mut new_root) = if self.old_size.is_power_of_two() {
+            (old.root, old.root)
+        } else {
+            let first = steps
+                .next()
+                .context("Consistency proof is missing its base step.")?;
+            ensure!(first.shared_with_old, "Malformed proof: base step must be shared.");
+            (first.step.hash, first.step.hash)
+        };
+
+        for entry in steps {
+            new_root = combine(new_root, &entry.step);
+            if entry.shared_with_old {
+                old_root = combine(old_root, &entry.step);
+            }
+        }
+
+        ensure!(old_root == old.root, "Consistency proof does not chain to the old root.");
+        ensure!(new_root == new.root, "Consistency proof does not chain to the new root.");
+        Ok(())
+    }
+
+    /// Wire encoding (`old_size || new_size || step count || steps`) for
+    /// carrying a consistency proof to a client over gRPC response
+    /// metadata, the same way `SignedTreeHead::to_bytes` does.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + 8 + 4 + self.steps.len() * 34);
+        out.extend_from_slice(&self.old_size.to_be_bytes());
+        out.extend_from_slice(&self.new_size.to_be_bytes());
+        out.extend_from_slice(&(self.steps.len() as u32).to_be_bytes());
+        for step in &self.steps {
+            out.extend_from_slice(&step.step.hash);
+            out.push(step.step.is_left as u8);
+            out.push(step.shared_with_old as u8);
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() >= 20, "Consistency proof header is truncated.");
+        let old_size = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+        let new_size = u64::from_be_bytes(bytes[8..16].try_into().unwrap());
+        let count = u32::from_be_bytes(bytes[16..20].try_into().unwrap()) as usize;
+        let body = &bytes[20..];
+        ensure!(body.len() == count * 34, "Consistency proof body has the wrong length.");
+        let steps = body
+            .chunks_exact(34)
+            .map(|chunk| ConsistencyStep {
+                step: PathStep {
+                    hash: chunk[0..32].try_into().unwrap(),
+                    is_left: chunk[32] != 0,
+                },
+                shared_with_old: chunk[33] != 0,
+            })
+            .collect();
+        Ok(ConsistencyProof {
+            old_size,
+            new_size,
+            steps,
+        })
+    }
+}
+
+/// A client's pin of the last signed tree head it has verified. Every later
+/// STH must either match the pinned one exactly (same tree size) or chain
+/// forward from it via a `ConsistencyProof` — an equivocating server that
+/// shows this client a different history than the one it pinned before
+/// gets caught the next time it checks in, rather than being trusted
+/// silently the way a fresh, unpinned verification would be.
+pub struct PinnedTreeHead {
+    verifying_key: VerifyingKey,
+    pinned: Option<SignedTreeHead>,
+}
+
+impl PinnedTreeHead {
+    pub fn new(verifying_key: VerifyingKey) -> Self {
+        PinnedTreeHead {
+            verifying_key,
+            pinned: None,
+        }
+    }
+
+    pub fn pinned(&self) -> Option<&SignedTreeHead> {
+        self.pinned.as_ref()
+    }
+
+    /// Verifies `new_sth`'s signature and, once a tree head is already
+    /// pinned, that it's consistent with the pin: either the exact same
+    /// tree, or reachable from it via `proof`. Only advances the pin once
+    /// both checks pass, so a failed check leaves the last-known-good pin
+    /// in place for the caller to act on (e.g. refuse to trust the bundle
+    /// that came with it).
+    pub fn verify_and_pin(
+        &mut self,
+        new_sth: &SignedTreeHead,
+        proof: Option<&ConsistencyProof>,
+    ) -> Result<()> {
+        new_sth.verify(&self.verifying_key)?;
+        if let Some(old) = &self.pinned {
+            ensure!(
+                new_sth.tree_size >= old.tree_size,
+                "Server's tree shrank since the last pin; possible equivocation."
+            );
+            if new_sth.tree_size == old.tree_size {
+                ensure!(
+                    new_sth.root == old.root,
+                    "Server returned a different root for a previously pinned tree size."
+                );
+            } else {
+                let proof = proof.context(
+                    "Server's tree grew since the last pin but sent no consistency proof.",
+                )?;
+                proof.verify(old, new_sth)?;
+            }
+        }
+        self.pinned = Some(new_sth.clone());
+        Ok(())
+    }
+}
+
+/// Append-only key directory, backed by the `kt_leaves` table.
+/// `SqliteBrongnal` builds one over its own connection, alongside the
+/// existing `users` table, each time it needs the log.
+pub struct KeyTransparencyLog<'a> {
+    connection: &'a Connection,
+}
+
+impl<'a> KeyTransparencyLog<'a> {
+    pub fn new(connection: &'a Connection) -> Result<Self> {
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS kt_leaves (
+             leaf_index INTEGER PRIMARY KEY,
+             identity STRING NOT NULL,
+             hash BLOB NOT NULL,
+             epoch INTEGER NOT NULL
+         )",
+                (),
+            )
+            .context("Creating kt_leaves table failed.")?;
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS kt_sths (
+             tree_size INTEGER PRIMARY KEY,
+             root BLOB NOT NULL,
+             epoch INTEGER NOT NULL,
+             signature BLOB NOT NULL
+         )",
+                (),
+            )
+            .context("Creating kt_sths table failed.")?;
+        Ok(KeyTransparencyLog { connection })
+    }
+
+    fn tree_size(&self) -> Result<u64> {
+        self.connection
+            .query_row("SELECT COUNT(*) FROM kt_leaves", (), |row| row.get(0))
+            .context("Failed to read kt_leaves count.")
+    }
+
+    /// Reads every leaf hash for the tree's first `size` leaves, in leaf
+    /// order, straight from the append-only `kt_leaves` table.
+    fn leaves_upto(&self, size: u64) -> Result<Vec<[u8; 32]>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT hash FROM kt_leaves WHERE leaf_index < ?1 ORDER BY leaf_index ASC")?;
+        statement
+            .query_map([size], |row| row.get::<_, Vec<u8>>(0))?
+            .map(|bytes| {
+                let bytes = bytes.context("Failed to read leaf hash.")?;
+                bytes
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Leaf hash was not 32 bytes."))
+            })
+            .collect()
+    }
+
+    /// Appends a leaf for `identity`'s current identity key, recomputes the
+    /// tree, and returns a freshly signed STH plus this leaf's inclusion
+    /// proof.
+    pub fn append(
+        &self,
+        signing_key: &SigningKey,
+        identity: &str,
+        identity_key: &VerifyingKey,
+    ) -> Result<(SignedTreeHead, InclusionProof)> {
+        let epoch = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let leaf_index = self.tree_size()?;
+        let hash = leaf_hash(identity, identity_key, epoch);
+        self.connection
+            .execute(
+                "INSERT INTO kt_leaves (leaf_index, identity, hash, epoch) VALUES (?1, ?2, ?3, ?4)",
+                (leaf_index, identity, hash.to_vec(), epoch),
+            )
+            .context("Failed to append key-transparency leaf.")?;
+
+        let tree_size = leaf_index + 1;
+        let leaves = self.leaves_upto(tree_size)?;
+        let root = mth(&leaves);
+        let sth = SignedTreeHead::sign(signing_key, root, tree_size, epoch);
+        self.connection
+            .execute(
+                "INSERT INTO kt_sths (tree_size, root, epoch, signature) VALUES (?1, ?2, ?3, ?4)",
+                (
+                    tree_size,
+                    root.to_vec(),
+                    epoch,
+                    sth.signature.to_bytes().to_vec(),
+                ),
+            )
+            .context("Failed to persist signed tree head.")?;
+
+        let mut path = Vec::new();
+        InclusionProof::build(&leaves, leaf_index, &mut path);
+        let proof = InclusionProof {
+            leaf_index,
+            tree_size,
+            path,
+        };
+        Ok((sth, proof))
+    }
+
+    /// Builds the inclusion proof for `leaf_index` against a tree of
+    /// `tree_size` leaves.
+    pub fn inclusion_proof(&self, leaf_index: u64, tree_size: u64) -> Result<InclusionProof> {
+        ensure!(leaf_index < tree_size, "Leaf index out of range.");
+        let leaves = self.leaves_upto(tree_size)?;
+        let mut path = Vec::new();
+        InclusionProof::build(&leaves, leaf_index, &mut path);
+        Ok(InclusionProof {
+            leaf_index,
+            tree_size,
+            path,
+        })
+    }
+
+    /// Builds a Merkle consistency proof between `old_size` and `new_size`,
+    /// so an auditor can confirm the log between those two STHs only had
+    /// leaves appended, never rewritten.
+    pub fn consistency_proof(&self, old_size: u64, new_size: u64) -> Result<ConsistencyProof> {
+        ensure!(old_size <= new_size, "old_size must not exceed new_size.");
+        let leaves = self.leaves_upto(new_size)?;
+        Ok(ConsistencyProof::build(&leaves, old_size, new_size))
+    }
+
+    pub fn latest_sth(&self) -> Result<Option<SignedTreeHead>> {
+        let tree_size = self.tree_size()?;
+        if tree_size == 0 {
+            return Ok(None);
+        }
+        let (root, epoch, signature): (Vec<u8>, u64, Vec<u8>) = self
+            .connection
+            .query_row(
+                "SELECT root, epoch, signature FROM kt_sths WHERE tree_size = ?1",
+                [tree_size],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .context("Missing STH for current tree size.")?;
+        Ok(Some(SignedTreeHead {
+            root: root.try_into().map_err(|_| anyhow::anyhow!("STH root was not 32 bytes."))?,
+            tree_size,
+            epoch,
+            signature: Signature::from_bytes(
+                signature
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("STH signature was not 64 bytes."))?,
+            ),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn open_log(connection: &Connection) -> KeyTransparencyLog<'_> {
+        KeyTransparencyLog::new(connection).unwrap()
+    }
+
+    #[test]
+    fn inclusion_proof_round_trips_as_the_tree_grows() {
+        let connection = Connection::open_in_memory().unwrap();
+        let server_key = SigningKey::generate(&mut OsRng);
+        let kt = open_log(&connection);
+
+        let mut identity_keys = Vec::new();
+        let mut sths = Vec::new();
+        for i in 0..9 {
+            let signing_key = SigningKey::generate(&mut OsRng);
+            let identity_key = signing_key.verifying_key();
+            let (sth, proof) = kt
+                .append(&server_key, &format!("user-{i}"), &identity_key)
+                .unwrap();
+            let leaf = leaf_hash(&format!("user-{i}"), &identity_key, sth.epoch);
+            proof.verify(leaf, &sth).unwrap();
+            identity_keys.push(identity_key);
+            sths.push(sth);
+        }
+
+        // Every earlier leaf must still verify against the final STH.
+        let final_sth = sths.last().unwrap().clone();
+        for (i, identity_key) in identity_keys.iter().enumerate() {
+            let proof = kt.inclusion_proof(i as u64, final_sth.tree_size).unwrap();
+            let leaf = leaf_hash(&format!("user-{i}"), identity_key, sths[i].epoch);
+            proof.verify(leaf, &final_sth).unwrap();
+        }
+    }
+
+    #[test]
+    fn inclusion_proof_rejects_a_substituted_key() {
+        let connection = Connection::open_in_memory().unwrap();
+        let server_key = SigningKey::generate(&mut OsRng);
+        let kt = open_log(&connection);
+        let real_key = SigningKey::generate(&mut OsRng).verifying_key();
+        let (sth, proof) = kt.append(&server_key, "alice", &real_key).unwrap();
+
+        let fake_key = SigningKey::generate(&mut OsRng).verifying_key();
+        let fake_leaf = leaf_hash("alice", &fake_key, sth.epoch);
+        assert!(proof.verify(fake_leaf, &sth).is_err());
+    }
+
+    #[test]
+    fn consistency_proof_chains_every_prefix_to_every_later_tree() {
+        let connection = Connection::open_in_memory().unwrap();
+        let server_key = SigningKey::generate(&mut OsRng);
+        let kt = open_log(&connection);
+
+        let mut sths = vec![];
+        for i in 0..12 {
+            let identity_key = SigningKey::generate(&mut OsRng).verifying_key();
+            let (sth, _proof) = kt.append(&server_key, &format!("user-{i}"), &identity_key).unwrap();
+            sths.push(sth);
+        }
+
+        for old in &sths {
+            for new in &sths {
+                if old.tree_size > new.tree_size {
+                    continue;
+                }
+                let proof = kt.consistency_proof(old.tree_size, new.tree_size).unwrap();
+                proof.verify(old, new).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn consistency_proof_rejects_a_mismatched_new_root() {
+        let connection = Connection::open_in_memory().unwrap();
+        let server_key = SigningKey::generate(&mut OsRng);
+        let kt = open_log(&connection);
+
+        for i in 0..5 {
+            let identity_key = SigningKey::generate(&mut OsRng).verifying_key();
+            kt.append(&server_key, &format!("user-{i}"), &identity_key).unwrap();
+        }
+        let old_sth = kt.latest_sth().unwrap().unwrap();
+        for i in 5..9 {
+            let identity_key = SigningKey::generate(&mut OsRng).verifying_key();
+            kt.append(&server_key, &format!("user-{i}"), &identity_key).unwrap();
+        }
+        let mut new_sth = kt.latest_sth().unwrap().unwrap();
+        new_sth.root[0] ^= 0xff;
+
+        let proof = kt
+            .consistency_proof(old_sth.tree_size, new_sth.tree_size)
+            .unwrap();
+        assert!(proof.verify(&old_sth, &new_sth).is_err());
+    }
+
+    #[test]
+    fn signed_tree_head_and_consistency_proof_round_trip_through_bytes() {
+        let connection = Connection::open_in_memory().unwrap();
+        let server_key = SigningKey::generate(&mut OsRng);
+        let kt = open_log(&connection);
+
+        for i in 0..5 {
+            let identity_key = SigningKey::generate(&mut OsRng).verifying_key();
+            kt.append(&server_key, &format!("user-{i}"), &identity_key).unwrap();
+        }
+        let old_sth = kt.latest_sth().unwrap().unwrap();
+        for i in 5..9 {
+            let identity_key = SigningKey::generate(&mut OsRng).verifying_key();
+            kt.append(&server_key, &format!("user-{i}"), &identity_key).unwrap();
+        }
+        let new_sth = kt.latest_sth().unwrap().unwrap();
+        let proof = kt
+            .consistency_proof(old_sth.tree_size, new_sth.tree_size)
+            .unwrap();
+
+        let decoded_old = SignedTreeHead::from_bytes(&old_sth.to_bytes()).unwrap();
+        let decoded_new = SignedTreeHead::from_bytes(&new_sth.to_bytes()).unwrap();
+        let decoded_proof = ConsistencyProof::from_bytes(&proof.to_bytes()).unwrap();
+        decoded_proof.verify(&decoded_old, &decoded_new).unwrap();
+    }
+
+    #[test]
+    fn pinned_tree_head_accepts_consistent_growth_and_rejects_equivocation() {
+        let connection = Connection::open_in_memory().unwrap();
+        let server_key = SigningKey::generate(&mut OsRng);
+        let kt = open_log(&connection);
+
+        for i in 0..4 {
+            let identity_key = SigningKey::generate(&mut OsRng).verifying_key();
+            kt.append(&server_key, &format!("user-{i}"), &identity_key).unwrap();
+        }
+        let first_sth = kt.latest_sth().unwrap().unwrap();
+
+        let mut pin = PinnedTreeHead::new(server_key.verifying_key());
+        pin.verify_and_pin(&first_sth, None).unwrap();
+        assert_eq!(pin.pinned().unwrap().tree_size, first_sth.tree_size);
+
+        for i in 4..8 {
+            let identity_key = SigningKey::generate(&mut OsRng).verifying_key();
+            kt.append(&server_key, &format!("user-{i}"), &identity_key).unwrap();
+        }
+        let second_sth = kt.latest_sth().unwrap().unwrap();
+        let proof = kt
+            .consistency_proof(first_sth.tree_size, second_sth.tree_size)
+            .unwrap();
+        pin.verify_and_pin(&second_sth, Some(&proof)).unwrap();
+        assert_eq!(pin.pinned().unwrap().tree_size, second_sth.tree_size);
+
+        // An equivocating STH: same tree size as the pin, different root.
+        let mut forked = second_sth.clone();
+        forked.root[0] ^= 0xff;
+        assert!(pin.verify_and_pin(&forked, None).is_err());
+
+        // A later, larger tree with no consistency proof must also be
+        // rejected, since a client can't tell it apart from a fork without
+        // one.
+        for i in 8..10 {
+            let identity_key = SigningKey::generate(&mut OsRng).verifying_key();
+            kt.append(&server_key, &format!("user-{i}"), &identity_key).unwrap();
+        }
+        let third_sth = kt.latest_sth().unwrap().unwrap();
+        assert!(pin.verify_and_pin(&third_sth, None).is_err());
+        // Still pinned at `second_sth` after both rejections.
+        assert_eq!(pin.pinned().unwrap().tree_size, second_sth.tree_size);
+    }
+}
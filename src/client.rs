@@ -4,6 +4,14 @@ use brongnal::x3dh::*;
 use brongnal::MemoryClient;
 use tarpc::{client, context};
 
+// This demo talks to the tarpc `X3DHServerClient`, whose `retrieve_messages`
+// is a one-shot call returning a plain `Vec<Message>` — there's no cursor,
+// ack, or reconnect concept anywhere in this service, unlike the gRPC
+// `Brongnal::retrieve_messages` the production server in `native/server`
+// exposes. A persisting reconnect loop belongs with the service that
+// actually has something to resume; see
+// `native/server/src/reconnect.rs::run_retrieve_loop` for that client.
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let (client_transport, _server_transport) = tarpc::transport::channel::unbounded();
@@ -5,9 +5,7 @@ use crate::bundle::*;
 use crate::traits::{X3DHClient, X3DHServer, X3DHServerClient};
 use crate::x3dh::*;
 use anyhow::{Context, Result};
-use blake2::{Blake2b512, Digest};
 use chacha20poly1305::aead::OsRng;
-use chacha20poly1305::{aead::KeyInit, ChaCha20Poly1305};
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use futures::prelude::*;
 use std::collections::HashMap;
@@ -21,15 +19,33 @@ use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSec
 
 mod aead;
 mod bundle;
+mod ratchet;
+mod sealed_sender;
 mod traits;
+mod transport;
 mod x3dh;
 
+use ratchet::DoubleRatchet;
+use sealed_sender::{DeliveryToken, SealedSenderEnvelope, SenderCertificate};
+use transport::{accept_obfuscated, connect_obfuscated, obfuscated_duplex, NodeIdentity};
+use tokio::net::TcpListener;
+use tokio_serde::formats::Bincode;
+
 #[derive(Clone)]
 struct MemoryServer {
     identity_key: Arc<Mutex<HashMap<String, VerifyingKey>>>,
     current_pre_key: Arc<Mutex<HashMap<String, SignedPreKey>>>,
     one_time_pre_keys: Arc<Mutex<HashMap<String, Vec<X25519PublicKey>>>>,
     messages: Arc<Mutex<HashMap<String, Vec<Message>>>>,
+    // Sealed-sender deliveries are queued separately from `messages` since
+    // the server never learns enough about them to know who the recipient
+    // would normally be keyed on beyond the identity they were sent to.
+    sealed_messages: Arc<Mutex<HashMap<String, Vec<SealedSenderEnvelope>>>>,
+    // Anonymous delivery tokens a recipient has registered, mapping back to
+    // the identity they route to. A sender only ever needs the token, not
+    // the recipient's real identity string.
+    delivery_tokens: Arc<Mutex<HashMap<DeliveryToken, String>>>,
+    server_signing_key: Arc<SigningKey>,
 }
 
 impl MemoryServer {
@@ -39,8 +55,65 @@ impl MemoryServer {
             current_pre_key: Arc::new(Mutex::new(HashMap::new())),
             one_time_pre_keys: Arc::new(Mutex::new(HashMap::new())),
             messages: Arc::new(Mutex::new(HashMap::new())),
+            sealed_messages: Arc::new(Mutex::new(HashMap::new())),
+            delivery_tokens: Arc::new(Mutex::new(HashMap::new())),
+            server_signing_key: Arc::new(SigningKey::generate(&mut OsRng)),
         }
     }
+
+    /// Issues a short-lived certificate binding `identity` to its registered
+    /// identity key, so it can send sealed messages the recipient can
+    /// authenticate without the server learning the sender at delivery time.
+    async fn issue_sender_certificate(&self, identity: String) -> Result<SenderCertificate> {
+        let identity_key = *self
+            .identity_key
+            .lock()
+            .await
+            .get(&identity)
+            .context("Cannot issue a certificate for an unregistered identity.")?;
+        SenderCertificate::issue(&self.server_signing_key, identity, identity_key)
+    }
+
+    /// Registers a fresh anonymous delivery token for `identity`, to hand
+    /// out to senders in place of the identity string itself.
+    async fn register_delivery_token(&self, identity: String) -> DeliveryToken {
+        let token = DeliveryToken::generate();
+        self.delivery_tokens.lock().await.insert(token, identity);
+        token
+    }
+
+    /// Unauthenticated by design: the caller proves nothing about who they
+    /// are, only that they hold a valid delivery token for the recipient.
+    /// The sealed envelope itself carries the sender's certificate,
+    /// encrypted to the recipient.
+    async fn send_sealed_message(
+        &self,
+        token: DeliveryToken,
+        envelope: SealedSenderEnvelope,
+    ) -> Result<()> {
+        let recipient_identity = self
+            .delivery_tokens
+            .lock()
+            .await
+            .get(&token)
+            .context("Unknown or expired delivery token.")?
+            .clone();
+        let mut sealed_messages = self.sealed_messages.lock().await;
+        let _ = sealed_messages.try_insert(recipient_identity.clone(), Vec::new());
+        sealed_messages
+            .get_mut(&recipient_identity)
+            .unwrap()
+            .push(envelope);
+        Ok(())
+    }
+
+    async fn retrieve_sealed_messages(&self, identity: String) -> Vec<SealedSenderEnvelope> {
+        self.sealed_messages
+            .lock()
+            .await
+            .remove(&identity)
+            .unwrap_or_default()
+    }
 }
 
 impl X3DHServer for MemoryServer {
@@ -189,53 +262,53 @@ impl X3DHClient for MemoryClient {
     }
 }
 
-fn ratchet(key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
-    let mut hasher = Blake2b512::new();
-    hasher.update(&key);
-    let blake2b_mac = hasher.finalize();
-    let mut l = [0; 32];
-    let mut r = [0; 32];
-    l.clone_from_slice(&blake2b_mac[0..32]);
-    r.clone_from_slice(&blake2b_mac[32..]);
-    (l, r)
-}
-
-struct SessionKeys<T> {
-    session_keys: HashMap<T, [u8; 32]>,
-}
-
-impl<Identity: Eq + std::hash::Hash> SessionKeys<Identity> {
-    fn set_session_key(&mut self, recipient_identity: Identity, secret_key: &[u8; 32]) {
-        self.session_keys.insert(recipient_identity, *secret_key);
-    }
-
-    fn get_encryption_key(&mut self, recipient_identity: &Identity) -> Result<ChaCha20Poly1305> {
-        let key = self
-            .session_keys
-            .get(recipient_identity)
-            .context("Session key not found.")?;
-        Ok(ChaCha20Poly1305::new_from_slice(key).unwrap())
-    }
-
-    fn destroy_session_key(&mut self, peer: &Identity) {
-        self.session_keys.remove(peer);
-    }
-}
-
 // Each defined rpc generates an async fn that serves the RPC
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let (client_transport, server_transport) = tarpc::transport::channel::unbounded();
-
-    let server = server::BaseChannel::with_defaults(server_transport);
-    tokio::spawn(
-        server
-            .execute(MemoryServer::new().serve())
-            .for_each(|response| async move {
-                tokio::spawn(response);
-            }),
-    );
+    // `X3DHServerClient`/the tarpc server run over a real, obfuscated TCP
+    // connection rather than the bare in-memory channel: the handshake and
+    // framing happen on an actual socket via `connect_obfuscated`/
+    // `accept_obfuscated`, and `obfuscated_duplex` bridges the resulting
+    // sealed-frame transport into the byte stream tarpc's `serde_transport`
+    // (and, identically, a tonic client/server) expects.
+    let server_obfs_secret = X25519StaticSecret::random_from_rng(OsRng);
+    let server_identity = NodeIdentity {
+        node_id: *b"brongnal-demo-node01",
+        public_key: X25519PublicKey::from(&server_obfs_secret),
+    };
+    let epoch_hour = 0;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = listener.local_addr()?;
+
+    // Kept alongside the tarpc channel so the demo can also exercise the
+    // sealed-sender methods, which aren't part of the `X3DHServer` trait and
+    // so have no tarpc-generated client; `MemoryServer` is cheap to `Clone`
+    // since every field is an `Arc`.
+    let memory_server = MemoryServer::new();
+    tokio::spawn({
+        let memory_server = memory_server.clone();
+        async move {
+            let obfs = match accept_obfuscated(&listener, &server_identity, &server_obfs_secret, epoch_hour).await {
+                Ok(obfs) => obfs,
+                Err(err) => {
+                    eprintln!("Failed to accept obfuscated connection: {err:?}");
+                    return;
+                }
+            };
+            let server_transport = tarpc::serde_transport::new(obfuscated_duplex(obfs), Bincode::default());
+            let server = server::BaseChannel::with_defaults(server_transport);
+            server
+                .execute(memory_server.serve())
+                .for_each(|response| async move {
+                    tokio::spawn(response);
+                })
+                .await;
+        }
+    });
 
+    let obfs = connect_obfuscated(server_addr, &server_identity, epoch_hour).await?;
+    let client_transport = tarpc::serde_transport::new(obfuscated_duplex(obfs), Bincode::default());
     let rpc_client = X3DHServerClient::new(client::Config::default(), client_transport).spawn();
     let mut bob = MemoryClient::new();
     rpc_client
@@ -261,7 +334,16 @@ async fn main() -> anyhow::Result<()> {
         .await??;
 
     let alice = MemoryClient::new();
-    let (_send_sk, message) = x3dh_initiate_send(bundle, &alice.get_identity_key()?, b"Hi Bob")?;
+    rpc_client
+        .set_spk(
+            context::current(),
+            "Alice".to_owned(),
+            alice.get_identity_key()?.verifying_key(),
+            alice.get_spk()?,
+        )
+        .await??;
+    let bob_ratchet_key = bob.get_spk()?.pre_key;
+    let (send_sk, message) = x3dh_initiate_send(bundle, &alice.get_identity_key()?, b"Hi Bob")?;
     rpc_client
         .send_message(context::current(), "Bob".to_owned(), message)
         .await??;
@@ -271,7 +353,7 @@ async fn main() -> anyhow::Result<()> {
         .await?;
     let message = &messages.get(0).unwrap();
 
-    let (_recv_sk, msg) = x3dh_initiate_recv(
+    let (recv_sk, msg) = x3dh_initiate_recv(
         &bob.get_identity_key()?.clone(),
         &bob.pre_key.clone(),
         &message.sender_identity_key,
@@ -284,5 +366,45 @@ async fn main() -> anyhow::Result<()> {
 
     println!("Alice sent to Bob: {}", String::from_utf8(msg)?);
 
+    // Hand the X3DH shared secret off to a Double Ratchet session so every
+    // later message gets its own key instead of reusing `send_sk`/`recv_sk`
+    // forever.
+    let mut alice_ratchet = DoubleRatchet::initialize_sender(send_sk, bob_ratchet_key);
+    let mut bob_ratchet = DoubleRatchet::initialize_receiver(recv_sk, bob.pre_key.clone());
+
+    let (header, ciphertext) = alice_ratchet.encrypt(b"Still me, Bob.")?;
+    let msg = bob_ratchet.decrypt(header, &ciphertext)?;
+    println!("Alice sent to Bob: {}", String::from_utf8(msg)?);
+
+    // Demonstrate sealed-sender delivery end to end: Bob hands out an
+    // anonymous delivery token instead of his identity, Alice gets a
+    // server-issued certificate and seals a message to the token, and the
+    // server never learns who sent it until Bob unseals it himself.
+    let alice_cert = memory_server
+        .issue_sender_certificate("Alice".to_owned())
+        .await?;
+    let bob_token = memory_server
+        .register_delivery_token("Bob".to_owned())
+        .await;
+    let bob_identity_secret = X25519StaticSecret::random_from_rng(OsRng);
+    let bob_identity_public = X25519PublicKey::from(&bob_identity_secret);
+    let envelope = sealed_sender::seal(&bob_identity_public, &alice_cert, b"Hi Bob, sealed.")?;
+    memory_server
+        .send_sealed_message(bob_token, envelope)
+        .await?;
+
+    let mut sealed = memory_server.retrieve_sealed_messages("Bob".to_owned()).await;
+    let envelope = sealed.pop().context("Expected a sealed message for Bob.")?;
+    let (sender_cert, plaintext) = sealed_sender::unseal(
+        &bob_identity_secret,
+        &memory_server.server_signing_key.verifying_key(),
+        &envelope,
+    )?;
+    println!(
+        "Bob received a sealed message from {}: {}",
+        sender_cert.identity,
+        String::from_utf8(plaintext)?
+    );
+
     Ok(())
 }
@@ -0,0 +1,326 @@
+// Sealed-sender delivery: lets a client deliver a message to a recipient
+// while the server learns only the recipient, not the sender, closing the
+// social-graph leak in `Message::sender_identity_key`.
+use anyhow::{ensure, Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use rand::{rngs::OsRng as RandOsRng, RngCore};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
+
+/// A per-recipient anonymous delivery token. A recipient registers one with
+/// the server and hands it out to senders in place of their real identity
+/// string, so `send_sealed_message` never has to take a plaintext identity
+/// as input: the server maps the token back to a recipient internally, but
+/// a captured token reveals nothing about the identity string it routes to.
+/// Registering a new token does not revoke earlier ones: every token stays
+/// valid for as long as the server keeps it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DeliveryToken(pub [u8; 16]);
+
+impl DeliveryToken {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 16];
+        RandOsRng.fill_bytes(&mut bytes);
+        DeliveryToken(bytes)
+    }
+}
+
+/// How long a sender certificate is valid for once issued. Short-lived so a
+/// leaked certificate can't be used to impersonate a sender indefinitely.
+pub const CERTIFICATE_LIFETIME_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// A server-signed statement that `identity_key` belongs to `identity`,
+/// valid until `expiration`. The server issues these to registered users;
+/// the recipient of a sealed message verifies one to learn who really sent
+/// it, without the server itself ever seeing that binding at delivery time.
+#[derive(Clone)]
+pub struct SenderCertificate {
+    pub identity: String,
+    pub identity_key: VerifyingKey,
+    pub expiration: u64,
+    pub signature: Signature,
+}
+
+impl SenderCertificate {
+    fn signed_bytes(identity: &str, identity_key: &VerifyingKey, expiration: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(identity.as_bytes());
+        bytes.extend_from_slice(identity_key.as_bytes());
+        bytes.extend_from_slice(&expiration.to_be_bytes());
+        bytes
+    }
+
+    /// Issued by the server for one of its registered users.
+    pub fn issue(
+        server_key: &SigningKey,
+        identity: String,
+        identity_key: VerifyingKey,
+    ) -> Result<Self> {
+        let expiration = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs()
+            + CERTIFICATE_LIFETIME_SECS;
+        let signature = server_key.sign(&Self::signed_bytes(&identity, &identity_key, expiration));
+        Ok(SenderCertificate {
+            identity,
+            identity_key,
+            expiration,
+            signature,
+        })
+    }
+
+    /// Verified by the recipient on unseal: rejects an expired certificate
+    /// or one not actually signed by the server.
+    pub fn verify(&self, server_verifying_key: &VerifyingKey) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        ensure!(now < self.expiration, "Sender certificate has expired.");
+        server_verifying_key
+            .verify(
+                &Self::signed_bytes(&self.identity, &self.identity_key, self.expiration),
+                &self.signature,
+            )
+            .context("Sender certificate signature is invalid.")?;
+        Ok(())
+    }
+
+    /// Wire encoding for carrying a certificate back to the client that
+    /// registered as response metadata, since there's no `.proto` field for
+    /// it: `identity_len(4) || identity || identity_key(32) ||
+    /// expiration(8) || signature(64)`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let identity_bytes = self.identity.as_bytes();
+        let mut out = Vec::with_capacity(4 + identity_bytes.len() + 32 + 8 + 64);
+        out.extend_from_slice(&(identity_bytes.len() as u32).to_be_bytes());
+        out.extend_from_slice(identity_bytes);
+        out.extend_from_slice(self.identity_key.as_bytes());
+        out.extend_from_slice(&self.expiration.to_be_bytes());
+        out.extend_from_slice(&self.signature.to_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() >= 4, "Sender certificate missing identity length.");
+        let identity_len = u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        let rest = &bytes[4..];
+        ensure!(rest.len() == identity_len + 32 + 8 + 64, "Sender certificate has the wrong length.");
+        let identity = String::from_utf8(rest[..identity_len].to_vec())
+            .context("Sender certificate identity is not valid UTF-8.")?;
+        let rest = &rest[identity_len..];
+        let identity_key = VerifyingKey::from_bytes(rest[0..32].try_into().unwrap())
+            .context("Sender certificate has an invalid identity key.")?;
+        let expiration = u64::from_be_bytes(rest[32..40].try_into().unwrap());
+        let signature = Signature::from_bytes(rest[40..104].try_into().unwrap());
+        Ok(SenderCertificate {
+            identity,
+            identity_key,
+            expiration,
+            signature,
+        })
+    }
+}
+
+/// The envelope a sealed-sender message is delivered in. The server sees
+/// only `ephemeral_key`, which is useless without the recipient's private
+/// identity key, and the recipient identity it was addressed to.
+pub struct SealedSenderEnvelope {
+    pub ephemeral_key: X25519PublicKey,
+    pub ciphertext: Vec<u8>,
+}
+
+impl SealedSenderEnvelope {
+    /// Wire encoding for carrying an envelope over an RPC that has no
+    /// `.proto` field for it: `ephemeral_key(32) || ciphertext`. The
+    /// ciphertext has no length prefix of its own since callers that batch
+    /// several envelopes together (see the server's `encode_sealed_batch`)
+    /// already need one to know where each envelope ends.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + self.ciphertext.len());
+        out.extend_from_slice(self.ephemeral_key.as_bytes());
+        out.extend_from_slice(&self.ciphertext);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() > 32, "Sealed envelope missing ephemeral key.");
+        let (ephemeral_key, ciphertext) = bytes.split_at(32);
+        Ok(SealedSenderEnvelope {
+            ephemeral_key: X25519PublicKey::from(<[u8; 32]>::try_from(ephemeral_key).unwrap()),
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+}
+
+/// Encrypts `{sender_cert, plaintext}` to `recipient_identity_key` using a
+/// key derived from a fresh ephemeral Curve25519 key and an X3DH-style DH
+/// with the recipient's identity key. Only the recipient, holding the
+/// matching private identity key, can recover the sender certificate.
+pub fn seal(
+    recipient_identity_key: &X25519PublicKey,
+    sender_cert: &SenderCertificate,
+    plaintext: &[u8],
+) -> Result<SealedSenderEnvelope> {
+    let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_key = X25519PublicKey::from(&ephemeral_secret);
+    let shared_secret = ephemeral_secret.diffie_hellman(recipient_identity_key);
+    let cipher = derive_cipher(shared_secret.as_bytes(), &ephemeral_key, recipient_identity_key)?;
+
+    let mut inner = Vec::new();
+    inner.extend_from_slice(sender_cert.identity.as_bytes());
+    inner.push(0); // NUL-terminate the identity so the reader can split it back out.
+    inner.extend_from_slice(sender_cert.identity_key.as_bytes());
+    inner.extend_from_slice(&sender_cert.expiration.to_be_bytes());
+    inner.extend_from_slice(&sender_cert.signature.to_bytes());
+    inner.extend_from_slice(plaintext);
+
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut ciphertext = cipher
+        .encrypt(&nonce, inner.as_slice())
+        .map_err(|_| anyhow::anyhow!("Failed to seal message."))?;
+    let mut out = nonce.to_vec();
+    out.append(&mut ciphertext);
+
+    Ok(SealedSenderEnvelope {
+        ephemeral_key,
+        ciphertext: out,
+    })
+}
+
+/// Recovers the plaintext and the sender's certificate from a sealed
+/// envelope, rejecting it if the certificate doesn't verify against the
+/// server's key. The caller should hand `plaintext` onward to
+/// `x3dh_initiate_recv`/the ratchet, exactly as with an unsealed message.
+pub fn unseal(
+    recipient_identity_key: &X25519StaticSecret,
+    server_verifying_key: &VerifyingKey,
+    envelope: &SealedSenderEnvelope,
+) -> Result<(SenderCertificate, Vec<u8>)> {
+    let shared_secret = recipient_identity_key.diffie_hellman(&envelope.ephemeral_key);
+    let cipher = derive_cipher(
+        shared_secret.as_bytes(),
+        &envelope.ephemeral_key,
+        &X25519PublicKey::from(recipient_identity_key),
+    )?;
+
+    ensure!(envelope.ciphertext.len() > 12, "Envelope missing nonce.");
+    let (nonce, ciphertext) = envelope.ciphertext.split_at(12);
+    let inner = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("Failed to unseal message."))?;
+
+    let nul_at = inner
+        .iter()
+        .position(|&b| b == 0)
+        .context("Sealed envelope missing identity terminator.")?;
+    let identity = String::from_utf8(inner[..nul_at].to_vec())
+        .context("Sealed envelope identity is not valid UTF-8.")?;
+    let rest = &inner[nul_at + 1..];
+    ensure!(rest.len() >= 32 + 8 + 64, "Sealed envelope truncated.");
+    let identity_key = VerifyingKey::from_bytes(rest[0..32].try_into().unwrap())
+        .context("Sealed envelope has an invalid identity key.")?;
+    let expiration = u64::from_be_bytes(rest[32..40].try_into().unwrap());
+    let signature = Signature::from_bytes(rest[40..104].try_into().unwrap());
+    let plaintext = rest[104..].to_vec();
+
+    let cert = SenderCertificate {
+        identity,
+        identity_key,
+        expiration,
+        signature,
+    };
+    cert.verify(server_verifying_key)?;
+    Ok((cert, plaintext))
+}
+
+fn derive_cipher(
+    shared_secret: &[u8; 32],
+    ephemeral_key: &X25519PublicKey,
+    recipient_identity_key: &X25519PublicKey,
+) -> Result<ChaCha20Poly1305> {
+    let hk = Hkdf::<Sha256>::new(None, shared_secret);
+    let mut okm = [0u8; 32];
+    let mut info = Vec::new();
+    info.extend_from_slice(ephemeral_key.as_bytes());
+    info.extend_from_slice(recipient_identity_key.as_bytes());
+    hk.expand(&info, &mut okm)
+        .expect("32 is a valid Sha256 HKDF output length.");
+    ChaCha20Poly1305::new_from_slice(&okm).context("Derived sealed-sender key is invalid.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_and_sender() -> (SigningKey, SenderCertificate, X25519StaticSecret) {
+        let server_key = SigningKey::generate(&mut OsRng);
+        let recipient_secret = X25519StaticSecret::random_from_rng(OsRng);
+        let sender_identity_key = SigningKey::generate(&mut OsRng);
+        let cert = SenderCertificate::issue(
+            &server_key,
+            "alice".to_owned(),
+            sender_identity_key.verifying_key(),
+        )
+        .unwrap();
+        (server_key, cert, recipient_secret)
+    }
+
+    #[test]
+    fn seal_unseal_round_trip() {
+        let (server_key, cert, recipient_secret) = server_and_sender();
+        let recipient_public = X25519PublicKey::from(&recipient_secret);
+
+        let envelope = seal(&recipient_public, &cert, b"hello bob").unwrap();
+        let (recovered_cert, plaintext) =
+            unseal(&recipient_secret, &server_key.verifying_key(), &envelope).unwrap();
+
+        assert_eq!(plaintext, b"hello bob");
+        assert_eq!(recovered_cert.identity, cert.identity);
+        assert_eq!(recovered_cert.identity_key, cert.identity_key);
+    }
+
+    #[test]
+    fn unseal_rejects_a_certificate_not_signed_by_the_server() {
+        let (_, cert, recipient_secret) = server_and_sender();
+        let other_server_key = SigningKey::generate(&mut OsRng);
+        let recipient_public = X25519PublicKey::from(&recipient_secret);
+
+        let envelope = seal(&recipient_public, &cert, b"hello bob").unwrap();
+        assert!(unseal(&recipient_secret, &other_server_key.verifying_key(), &envelope).is_err());
+    }
+
+    #[test]
+    fn sender_certificate_round_trips_through_bytes() {
+        let (server_key, cert, _) = server_and_sender();
+        let decoded = SenderCertificate::from_bytes(&cert.to_bytes()).unwrap();
+        decoded.verify(&server_key.verifying_key()).unwrap();
+        assert_eq!(decoded.identity, cert.identity);
+        assert_eq!(decoded.identity_key, cert.identity_key);
+        assert_eq!(decoded.expiration, cert.expiration);
+    }
+
+    #[test]
+    fn sealed_envelope_round_trips_through_bytes() {
+        let (_, cert, recipient_secret) = server_and_sender();
+        let recipient_public = X25519PublicKey::from(&recipient_secret);
+        let envelope = seal(&recipient_public, &cert, b"hello bob").unwrap();
+
+        let decoded = SealedSenderEnvelope::from_bytes(&envelope.to_bytes()).unwrap();
+        assert_eq!(decoded.ephemeral_key, envelope.ephemeral_key);
+        assert_eq!(decoded.ciphertext, envelope.ciphertext);
+    }
+
+    #[test]
+    fn unseal_rejects_envelope_opened_by_the_wrong_recipient() {
+        let (server_key, cert, _) = server_and_sender();
+        let recipient_public = X25519PublicKey::from(&X25519StaticSecret::random_from_rng(OsRng));
+        let wrong_secret = X25519StaticSecret::random_from_rng(OsRng);
+
+        let envelope = seal(&recipient_public, &cert, b"hello bob").unwrap();
+        assert!(unseal(&wrong_secret, &server_key.verifying_key(), &envelope).is_err());
+    }
+}
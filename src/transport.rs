@@ -0,0 +1,512 @@
+// Obfuscated transport that hides Brongnal's payloads and key exchange from
+// a passive network observer, and keeps active probes that lack the node's
+// public key from completing a handshake.
+//
+// This is modeled on obfs4/ntor: the client's handshake starts with an
+// Elligator2-encoded Curve25519 public key (so it looks uniformly random),
+// padded to a random length and authenticated with an HMAC; the server finds
+// the frame's end by scanning for `HANDSHAKE_MARK`. The stream as a whole is
+// not indistinguishable from random: the mark is a fixed 8-byte ASCII string,
+// and every later record starts with a plaintext 4-byte length.
+use anyhow::{ensure, Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, Mutex};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Minimum and maximum amount of random padding appended after the
+/// handshake's Elligator2 representative, to keep the frame length from
+/// being a fingerprint on its own.
+const MIN_PADDING: usize = 0;
+const MAX_PADDING: usize = 256;
+
+/// A server's long-term identity, distributed to clients out of band (like a
+/// certificate) so they can authenticate the handshake and locate the
+/// server's mark in the byte stream.
+#[derive(Clone, Copy, Debug)]
+pub struct NodeIdentity {
+    pub node_id: [u8; 20],
+    pub public_key: X25519PublicKey,
+}
+
+/// A frame-moving transport that an `ObfuscatedTransport` wraps:
+/// `MemoryTransport` in-process or `TcpTransport` over a socket.
+/// `ObfuscatedTransport` does not implement this trait itself, and RPC
+/// clients are not built over it directly; `obfuscated_duplex` turns an
+/// `ObfuscatedTransport` into the byte stream they run over.
+pub trait Transport: Send + Sync {
+    fn send<'a>(&'a self, frame: &'a [u8]) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+    fn recv<'a>(&'a self) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>>;
+}
+
+/// Per-connection keys derived from the ntor handshake: one direction each,
+/// since reusing a single key for both directions risks nonce reuse between
+/// client and server.
+struct FrameKeys {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+}
+
+/// Wraps an inner byte-stream transport with the obfs4-style handshake and
+/// encrypted framing described above. Built once per connection, after
+/// `client_handshake`/`server_handshake` has produced the shared secret.
+pub struct ObfuscatedTransport<T> {
+    inner: T,
+    keys: FrameKeys,
+}
+
+/// Computes the MAC Brongnal uses to authenticate (and let the server find)
+/// a handshake frame: `HMAC-SHA256` keyed on the server's public key over
+/// the representative, the padding, and the current epoch hour. Binding the
+/// epoch hour keeps a captured handshake from being replayed indefinitely.
+fn handshake_mac(server_public: &X25519PublicKey, body: &[u8], epoch_hour: u64) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(server_public.as_bytes())
+        .expect("HMAC accepts a 32-byte key.");
+    mac.update(body);
+    mac.update(&epoch_hour.to_be_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+/// The "mark" appended after the MAC: a fixed marker the server scans for
+/// to find the frame boundary in a byte stream, rather than assuming a
+/// fixed handshake length (which would itself be a fingerprint once padding
+/// length varies).
+const HANDSHAKE_MARK: &[u8; 8] = b"brongnl1";
+
+/// Client side of the handshake: generates an ephemeral keypair, encodes its
+/// public key with Elligator2, pads it to a random length, and appends the
+/// MAC and mark. Returns the bytes to send and the send/recv frame ciphers
+/// derived from `DH(client_eph, server_static)` through an HKDF keyed on the
+/// node ID.
+pub fn client_handshake(
+    server: &NodeIdentity,
+    epoch_hour: u64,
+) -> Result<(Vec<u8>, ChaCha20Poly1305, ChaCha20Poly1305)> {
+    let (client_secret, representative) = representable_ephemeral_secret()
+        .context("Failed to generate an Elligator2-representable ephemeral key.")?;
+
+    let mut padding = vec![0u8; OsRng.next_u32() as usize % (MAX_PADDING - MIN_PADDING + 1) + MIN_PADDING];
+    OsRng.fill_bytes(&mut padding);
+
+    let mut body = representative.to_vec();
+    body.extend_from_slice(&padding);
+    let mac = handshake_mac(&server.public_key, &body, epoch_hour);
+
+    let mut frame = body;
+    frame.extend_from_slice(&mac);
+    frame.extend_from_slice(HANDSHAKE_MARK);
+
+    let shared_secret = client_secret.diffie_hellman(&server.public_key);
+    let (send, recv) = derive_frame_keys(shared_secret.as_bytes(), &server.node_id, true)?;
+    Ok((frame, send, recv))
+}
+
+/// Server side of the handshake: scans `stream` for `HANDSHAKE_MARK`,
+/// verifies the MAC over everything before it, recovers the client's
+/// ephemeral public key from the Elligator2 representative, and derives the
+/// same pair of frame keys (swapped relative to the client's).
+pub fn server_handshake(
+    identity: &NodeIdentity,
+    our_secret: &X25519StaticSecret,
+    stream: &[u8],
+    epoch_hour: u64,
+) -> Result<(usize, ChaCha20Poly1305, ChaCha20Poly1305)> {
+    let mark_at = stream
+        .windows(HANDSHAKE_MARK.len())
+        .position(|w| w == HANDSHAKE_MARK)
+        .context("No handshake mark found in stream; not a Brongnal client.")?;
+    let mac_at = mark_at
+        .checked_sub(32)
+        .context("Stream too short to contain a MAC before the mark.")?;
+    let (body, rest) = stream.split_at(mac_at);
+    let (mac, _mark) = rest.split_at(32);
+
+    let expected = handshake_mac(&identity.public_key, body, epoch_hour);
+    ensure!(
+        constant_time_eq(mac, &expected),
+        "Handshake MAC did not verify; dropping connection."
+    );
+
+    ensure!(body.len() >= 32, "Handshake body missing representative.");
+    let (representative, _padding) = body.split_at(32);
+    let client_public = elligator2_decode(representative)?;
+    let shared_secret = our_secret.diffie_hellman(&client_public);
+    let (send, recv) = derive_frame_keys(shared_secret.as_bytes(), &identity.node_id, false)?;
+
+    Ok((mark_at + HANDSHAKE_MARK.len(), send, recv))
+}
+
+/// Expands the ntor shared secret plus the node ID (as context, binding the
+/// keys to the specific server identity) into independent send/recv frame
+/// keys. `is_client` picks which half of the HKDF output is "send" so the
+/// two sides end up with swapped but matching key pairs.
+fn derive_frame_keys(
+    shared_secret: &[u8],
+    node_id: &[u8; 20],
+    is_client: bool,
+) -> Result<(ChaCha20Poly1305, ChaCha20Poly1305)> {
+    let hk = Hkdf::<Sha256>::new(Some(node_id), shared_secret);
+    let mut okm = [0u8; 64];
+    hk.expand(b"brongnal-obfs-frame-keys", &mut okm)
+        .expect("64 is a valid Sha256 HKDF output length.");
+    let client_to_server = ChaCha20Poly1305::new_from_slice(&okm[0..32])
+        .context("Derived client->server key is invalid.")?;
+    let server_to_client = ChaCha20Poly1305::new_from_slice(&okm[32..64])
+        .context("Derived server->client key is invalid.")?;
+    Ok(if is_client {
+        (client_to_server, server_to_client)
+    } else {
+        (server_to_client, client_to_server)
+    })
+}
+
+impl<T> ObfuscatedTransport<T> {
+    pub fn new(inner: T, send: ChaCha20Poly1305, recv: ChaCha20Poly1305) -> Self {
+        ObfuscatedTransport {
+            inner,
+            keys: FrameKeys { send, recv },
+        }
+    }
+
+    /// Encrypts `payload` into a length-prefixed, authenticated record for
+    /// writing to the inner stream.
+    pub fn seal_frame(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut ciphertext = self
+            .keys
+            .send
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow::anyhow!("Failed to seal frame."))?;
+        let mut frame = Vec::with_capacity(4 + 12 + ciphertext.len());
+        frame.extend_from_slice(&(ciphertext.len() as u32 + 12).to_be_bytes());
+        frame.extend_from_slice(&nonce);
+        frame.append(&mut ciphertext);
+        Ok(frame)
+    }
+
+    /// Decrypts a record produced by `seal_frame` on the peer's matching
+    /// `ObfuscatedTransport`. `record` still carries `seal_frame`'s leading
+    /// 4-byte length prefix (a `Transport` just moves the bytes verbatim, it
+    /// doesn't know the frame format), so that has to be stripped before the
+    /// nonce and ciphertext line up.
+    pub fn open_frame(&self, record: &[u8]) -> Result<Vec<u8>> {
+        ensure!(record.len() > 4 + 12, "Frame missing length prefix or nonce.");
+        let (_len, rest) = record.split_at(4);
+        let (nonce, ciphertext) = rest.split_at(12);
+        self.keys
+            .recv
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Failed to open frame."))
+    }
+}
+
+impl<T: Transport> ObfuscatedTransport<T> {
+    /// Seals `payload` and writes it to the inner transport.
+    pub async fn send_sealed(&self, payload: &[u8]) -> Result<()> {
+        let frame = self.seal_frame(payload)?;
+        self.inner.send(&frame).await
+    }
+
+    /// Reads one record from the inner transport and opens it.
+    pub async fn recv_sealed(&self) -> Result<Vec<u8>> {
+        let frame = self.inner.recv().await?;
+        self.open_frame(&frame)
+    }
+}
+
+/// An in-memory, in-process duplex `Transport`: two ends of a pair of
+/// channels, useful for exercising the handshake and frame encryption
+/// end to end without a real socket. `TcpTransport` below is the real-socket
+/// counterpart that the demo's `X3DHServerClient` and tarpc server run over.
+pub struct MemoryTransport {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (tx_a, rx_a) = mpsc::channel(8);
+        let (tx_b, rx_b) = mpsc::channel(8);
+        (
+            MemoryTransport {
+                tx: tx_a,
+                rx: Mutex::new(rx_b),
+            },
+            MemoryTransport {
+                tx: tx_b,
+                rx: Mutex::new(rx_a),
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send<'a>(&'a self, frame: &'a [u8]) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            self.tx
+                .send(frame.to_vec())
+                .await
+                .map_err(|_| anyhow::anyhow!("Peer transport closed."))
+        })
+    }
+
+    fn recv<'a>(&'a self) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(async move { self.rx.lock().await.recv().await.context("Peer transport closed.") })
+    }
+}
+
+/// A `Transport` over a real TCP connection. `seal_frame` already prefixes
+/// every frame with its own length, so `send` writes the frame as-is and
+/// `recv` just reads that length back out before reading the rest — TCP, as
+/// a byte stream, otherwise has no notion of where one frame ends and the
+/// next begins.
+pub struct TcpTransport {
+    read: Mutex<OwnedReadHalf>,
+    write: Mutex<OwnedWriteHalf>,
+}
+
+impl TcpTransport {
+    pub fn new(stream: TcpStream) -> Self {
+        let (read, write) = stream.into_split();
+        TcpTransport {
+            read: Mutex::new(read),
+            write: Mutex::new(write),
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send<'a>(&'a self, frame: &'a [u8]) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            self.write
+                .lock()
+                .await
+                .write_all(frame)
+                .await
+                .context("Failed to write frame to TCP stream.")
+        })
+    }
+
+    fn recv<'a>(&'a self) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut read = self.read.lock().await;
+            let mut len_bytes = [0u8; 4];
+            read.read_exact(&mut len_bytes)
+                .await
+                .context("Peer closed the connection before sending a frame.")?;
+            let body_len = u32::from_be_bytes(len_bytes) as usize;
+            let mut body = vec![0u8; body_len];
+            read.read_exact(&mut body)
+                .await
+                .context("Peer closed the connection mid-frame.")?;
+            let mut frame = len_bytes.to_vec();
+            frame.extend_from_slice(&body);
+            Ok(frame)
+        })
+    }
+}
+
+/// The largest a handshake frame can legally be: representative, the widest
+/// possible padding, the MAC, and the mark.
+const MAX_HANDSHAKE_LEN: usize = 32 + MAX_PADDING + 32 + HANDSHAKE_MARK.len();
+
+/// Reads one byte at a time off `stream` until `HANDSHAKE_MARK` appears at
+/// the tail of what's been read. Byte-at-a-time is wasteful, but it's the
+/// simplest way to stop reading at exactly the handshake's end without
+/// knowing its length up front, and this only runs once per connection.
+async fn read_handshake_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(64);
+    loop {
+        let mut byte = [0u8; 1];
+        stream
+            .read_exact(&mut byte)
+            .await
+            .context("Connection closed during the handshake.")?;
+        buf.push(byte[0]);
+        if buf.len() >= HANDSHAKE_MARK.len() && buf[buf.len() - HANDSHAKE_MARK.len()..] == *HANDSHAKE_MARK {
+            return Ok(buf);
+        }
+        ensure!(
+            buf.len() <= MAX_HANDSHAKE_LEN,
+            "No handshake mark found within the maximum handshake length; not a Brongnal client."
+        );
+    }
+}
+
+/// Connects to `addr`, performs the client side of the obfuscated handshake
+/// directly over the raw socket (so the handshake is not wrapped in the
+/// length-prefixed framing that starts after it), and
+/// returns the resulting transport ready for `send_sealed`/`recv_sealed`.
+pub async fn connect_obfuscated(
+    addr: impl ToSocketAddrs,
+    server: &NodeIdentity,
+    epoch_hour: u64,
+) -> Result<ObfuscatedTransport<TcpTransport>> {
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .context("Failed to connect to server.")?;
+    let (frame, send, recv) = client_handshake(server, epoch_hour)?;
+    stream
+        .write_all(&frame)
+        .await
+        .context("Failed to send handshake.")?;
+    Ok(ObfuscatedTransport::new(TcpTransport::new(stream), send, recv))
+}
+
+/// Accepts one connection on `listener` and performs the server side of the
+/// obfuscated handshake, returning the resulting transport.
+pub async fn accept_obfuscated(
+    listener: &TcpListener,
+    identity: &NodeIdentity,
+    our_secret: &X25519StaticSecret,
+    epoch_hour: u64,
+) -> Result<ObfuscatedTransport<TcpTransport>> {
+    let (mut stream, _addr) = listener
+        .accept()
+        .await
+        .context("Failed to accept a TCP connection.")?;
+    let handshake = read_handshake_frame(&mut stream).await?;
+    let (_consumed, send, recv) = server_handshake(identity, our_secret, &handshake, epoch_hour)?;
+    Ok(ObfuscatedTransport::new(TcpTransport::new(stream), send, recv))
+}
+
+/// Bridges an `ObfuscatedTransport` into a plain `AsyncRead + AsyncWrite`
+/// byte stream, so protocol stacks that only know how to run over a byte
+/// stream — tarpc's `serde_transport`, tonic's HTTP/2 client/server — can be
+/// layered on top of an obfuscated connection exactly like they would over a
+/// bare `TcpStream`. Two background tasks pump bytes between the returned
+/// `DuplexStream` and the underlying sealed frames.
+pub fn obfuscated_duplex<T: Transport + 'static>(
+    transport: ObfuscatedTransport<T>,
+) -> tokio::io::DuplexStream {
+    let (application_side, service_side) = tokio::io::duplex(64 * 1024);
+    let transport = Arc::new(transport);
+
+    let (mut service_read, mut service_write) = tokio::io::split(service_side);
+    let outbound = transport.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            let n = match service_read.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            if outbound.send_sealed(&buf[..n]).await.is_err() {
+                return;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        loop {
+            let payload = match transport.recv_sealed().await {
+                Ok(payload) => payload,
+                Err(_) => return,
+            };
+            if service_write.write_all(&payload).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    application_side
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Encodes an ephemeral Curve25519 public key with Elligator2 so the
+/// handshake bytes are indistinguishable from random. Not every point on
+/// the curve is representable (roughly half aren't), so this can fail for
+/// a given key; `representable_ephemeral_secret` is what callers actually
+/// use, since it resamples a fresh key until one succeeds.
+fn elligator2_encode(secret: &EphemeralSecret) -> Result<[u8; 32]> {
+    curve25519_elligator2::MontgomeryPoint::from(X25519PublicKey::from(secret))
+        .to_representative()
+        .map(|r| r.to_bytes())
+        .into_option()
+        .context("Ephemeral key has no Elligator2 representative.")
+}
+
+/// Generates ephemeral keys until one happens to be Elligator2-representable
+/// (roughly a 50% chance each try, so this almost always returns on the
+/// first or second attempt) and returns it alongside its representative.
+fn representable_ephemeral_secret() -> Result<(EphemeralSecret, [u8; 32])> {
+    const MAX_ATTEMPTS: u32 = 32;
+    for _ in 0..MAX_ATTEMPTS {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        if let Ok(representative) = elligator2_encode(&secret) {
+            return Ok((secret, representative));
+        }
+    }
+    anyhow::bail!("No Elligator2-representable key found in {MAX_ATTEMPTS} attempts.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resampling_always_finds_a_representable_key() {
+        for _ in 0..20 {
+            let (secret, representative) = representable_ephemeral_secret().unwrap();
+            assert_eq!(elligator2_encode(&secret).unwrap(), representative);
+            assert_eq!(
+                elligator2_decode(&representative).unwrap(),
+                X25519PublicKey::from(&secret)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_and_framing_round_trip_over_a_memory_transport() {
+        let server_secret = X25519StaticSecret::random_from_rng(OsRng);
+        let identity = NodeIdentity {
+            node_id: [9u8; 20],
+            public_key: X25519PublicKey::from(&server_secret),
+        };
+        let epoch_hour = 0;
+
+        let (client_transport, server_transport) = MemoryTransport::pair();
+
+        let (frame, client_send, client_recv) = client_handshake(&identity, epoch_hour).unwrap();
+        client_transport.send(&frame).await.unwrap();
+
+        let received = server_transport.recv().await.unwrap();
+        let (_consumed, server_send, server_recv) =
+            server_handshake(&identity, &server_secret, &received, epoch_hour).unwrap();
+
+        let client = ObfuscatedTransport::new(client_transport, client_send, client_recv);
+        let server = ObfuscatedTransport::new(server_transport, server_send, server_recv);
+
+        client.send_sealed(b"hello from the client").await.unwrap();
+        let plaintext = server.recv_sealed().await.unwrap();
+        assert_eq!(plaintext, b"hello from the client");
+
+        server.send_sealed(b"hello from the server").await.unwrap();
+        let plaintext = client.recv_sealed().await.unwrap();
+        assert_eq!(plaintext, b"hello from the server");
+    }
+}
+
+fn elligator2_decode(representative: &[u8]) -> Result<X25519PublicKey> {
+    ensure!(representative.len() == 32, "Representative must be 32 bytes.");
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(representative);
+    let point = curve25519_elligator2::Representative::from(bytes).to_montgomery();
+    Ok(X25519PublicKey::from(point.to_bytes()))
+}